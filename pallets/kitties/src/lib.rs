@@ -2,50 +2,118 @@
 
 use codec::{Decode, Encode};
 use frame_support::{
-    decl_error, decl_event, decl_module, decl_storage, dispatch::DispatchResult, ensure,
-    traits::Randomness, RuntimeDebug, StorageDoubleMap, StorageValue,
+    decl_error, decl_event, decl_module, decl_storage,
+    dispatch::{DispatchError, DispatchResult},
+    ensure,
+    traits::{Currency, ExistenceRequirement, Get, Randomness},
+    IterableStorageDoubleMap, RuntimeDebug, StorageDoubleMap, StorageValue,
 };
 use frame_system::ensure_signed;
 use sp_io::hashing::blake2_128;
 
+/// Generations beyond this are capped for cooldown-scaling purposes, so the cooldown stops
+/// growing long before it could overflow the block number type.
+const MAX_COOLDOWN_GENERATION: u16 = 10;
+
 #[derive(Encode, Decode, Clone, RuntimeDebug, PartialEq, Eq)]
-pub struct Kitty(pub [u8; 16]);
+pub struct Kitty<BlockNumber> {
+    pub dna: [u8; 16],
+    /// Generation, i.e. `max(parent generations) + 1`. `0` for kitties minted by `create`.
+    pub gen: u16,
+    /// Block at which this kitty is next allowed to breed.
+    pub cooldown_end: BlockNumber,
+}
 
 #[derive(Encode, Decode, Clone, Copy, RuntimeDebug, PartialEq, Eq)]
-enum KittyGender {
+pub enum KittyGender {
     M,
     F,
 }
-impl Kitty {
+
+/// Named traits decoded from a kitty's DNA, for clients that want to render a kitty without
+/// re-deriving the bit math themselves.
+#[derive(Encode, Decode, Clone, RuntimeDebug, PartialEq, Eq)]
+pub struct KittyAttributes {
+    pub fur_color: u16,
+    pub eye_shape: u8,
+    pub rarity_score: u8,
+}
+
+impl<BlockNumber> Kitty<BlockNumber> {
     fn gender(&self) -> KittyGender {
-        if self.0[0] % 2 == 0 {
+        if self.dna[0] % 2 == 0 {
             KittyGender::F
         } else {
             KittyGender::M
         }
     }
+
+    /// Decode this kitty's named traits from its DNA: fur color from bytes 1-2, eye shape from
+    /// byte 3, and a rarity score folded from the remaining bytes.
+    pub fn attributes(&self) -> KittyAttributes {
+        KittyAttributes {
+            fur_color: u16::from_be_bytes([self.dna[1], self.dna[2]]),
+            eye_shape: self.dna[3],
+            rarity_score: self.dna[4..].iter().fold(0u8, |acc, byte| acc ^ byte),
+        }
+    }
 }
 
 type KittyId = u32;
+pub type BalanceOf<T> =
+    <<T as Config>::Currency as Currency<<T as frame_system::Config>::AccountId>>::Balance;
+
 pub trait Config: frame_system::Config {
     type Event: From<Event<Self>> + Into<<Self as frame_system::Config>::Event>;
+    /// The currency used to pay for kitties on the marketplace.
+    type Currency: Currency<Self::AccountId>;
+    /// Base cooldown that both parents and the child must wait after a breed before they may
+    /// breed again. Scaled up per-generation, see `Module::cooldown_for`. Kitties minted by
+    /// `create` start with no cooldown.
+    type CooldownDuration: Get<Self::BlockNumber>;
 }
 
 decl_storage! {
     trait Store for Module<T: Config> as Kitties {
         /// Stores all the kitties, key is the kitty id
-        pub Kitties get(fn kitties): double_map hasher(blake2_128_concat) T::AccountId, hasher(blake2_128_concat) KittyId => Option<Kitty>;
+        pub Kitties get(fn kitties): double_map hasher(blake2_128_concat) T::AccountId, hasher(blake2_128_concat) KittyId => Option<Kitty<T::BlockNumber>>;
         /// Stores the next kitty ID
         pub NextKittyId get(fn next_kitty_id): KittyId;
+        /// Bumped on every `create`/`breed` call and mixed into the DNA payload so two mints in
+        /// the same block (where `random_seed` is constant) don't derive the same hash.
+        pub Nonce get(fn nonce): u64;
+        /// How many kitties an account owns
+        pub OwnedKittiesCount get(fn owned_kitties_count): map hasher(blake2_128_concat) T::AccountId => u64;
+        /// An index into an owner's kitties, for enumeration. Maintained with swap-and-pop so
+        /// it never has gaps: index `i` always points at one of the owner's `n` live kitties.
+        pub OwnedKittiesIndex get(fn owned_kitties_index): double_map hasher(blake2_128_concat) T::AccountId, hasher(blake2_128_concat) u64 => KittyId;
+        /// The price an owner is asking for a kitty, if it is listed for sale.
+        pub KittyPrices get(fn kitty_price): double_map hasher(blake2_128_concat) T::AccountId, hasher(blake2_128_concat) KittyId => Option<BalanceOf<T>>;
+        /// All kitties, keyed by id alone, so identity and DNA persist across ownership changes.
+        pub AllKitties get(fn all_kitties): map hasher(blake2_128_concat) KittyId => Option<Kitty<T::BlockNumber>>;
+        /// Current owner of a kitty, looked up by id alone.
+        pub KittyOwner get(fn kitty_owner): map hasher(blake2_128_concat) KittyId => Option<T::AccountId>;
+        /// Total number of kitties ever minted.
+        pub AllKittiesCount get(fn all_kitties_count): u64;
+        /// An index over every kitty, for full-collection enumeration.
+        pub AllKittiesByIndex get(fn all_kitties_by_index): map hasher(blake2_128_concat) u64 => KittyId;
     }
 }
 
 decl_event! {
     pub enum Event<T> where
         <T as frame_system::Config>::AccountId,
+        <T as frame_system::Config>::BlockNumber,
+        Balance = BalanceOf<T>,
     {
-        /// A kitty is created. \[owner, kitty_id, kitty\]
-        KittyCreated(AccountId, u32, Kitty),
+        /// A kitty is created. \[owner, kitty_id, kitty, gender, attributes\]
+        KittyCreated(AccountId, u32, Kitty<BlockNumber>, KittyGender, KittyAttributes),
+        /// A kitty is transferred. \[from, to, kitty_id\]
+        KittyTransferred(AccountId, AccountId, KittyId),
+        /// A kitty's price is set, or it is de-listed if the price is `None`. \[owner, kitty_id, price\]
+        PriceSet(AccountId, KittyId, Option<Balance>),
+        /// A kitty is bought. \[buyer, seller, kitty_id, price\]
+        KittyBought(AccountId, AccountId, KittyId, Balance),
     }
 }
 
@@ -55,6 +123,14 @@ decl_error! {
         SameGenderParents,
         MaxKittiesReachedNow,
         KittyNotExixting,
+        OwnedKittiesCountOverflow,
+        OwnedKittiesCountUnderflow,
+        NotForSale,
+        NotOwner,
+        BidTooLow,
+        ParentOnCooldown,
+        AllKittiesCountOverflow,
+        GenerationOverflow,
     }
 }
 
@@ -74,22 +150,24 @@ decl_module! {
             ensure!(!(NextKittyId::get() as u32 == u32::MAX), Error::<T>::KittiesIdOverflow);
             // return Err(Error::<T>::KittiesIdOverflow.into());
 
-            // Generate a random 128bit value
-            let payload = (
-                <pallet_randomness_collective_flip::Module<T> as Randomness<T::Hash>>::random_seed(),
-                &sender,
-                <frame_system::Module<T>>::extrinsic_index(),
-            );
-            let dna = payload.using_encoded(blake2_128);
+            // Generate a random, collision-free 128bit value
+            let dna = Self::mint_unique_dna(&sender);
 
-            // Create and store kitty
-            let kitty = Kitty(dna);
+            // Create and store kitty. A freshly minted gen-0 kitty may breed immediately; only
+            // breeding itself puts a kitty on cooldown.
+            let kitty = Kitty {
+                dna,
+                gen: 0,
+                cooldown_end: <frame_system::Module<T>>::block_number(),
+            };
             let kitty_id = Self::next_kitty_id();
-            Kitties::<T>::insert(&sender, kitty_id, kitty.clone());
+            Self::register_kitty(&sender, kitty_id, &kitty)?;
             NextKittyId::put(kitty_id + 1);
 
             // Emit event
-            Self::deposit_event(RawEvent::KittyCreated(sender, kitty_id, kitty))
+            let gender = kitty.gender();
+            let attributes = kitty.attributes();
+            Self::deposit_event(RawEvent::KittyCreated(sender, kitty_id, kitty, gender, attributes))
         }
 
         #[weight = 89_000]
@@ -97,8 +175,64 @@ decl_module! {
             let user = ensure_signed(origin)?;
             let kitty_1 = Kitties::<T>::get(&user, kitti_id_1 as KittyId).ok_or(Error::<T>::KittyNotExixting)?;
             let kitty_2 = Kitties::<T>::get(&user, kitty_id_2 as KittyId).ok_or(Error::<T>::KittyNotExixting)?;
-            let (kitty_created, new_kitty_id) = Self::breed_new(user.clone(), &kitty_1, &kitty_2)?;
-            Self::deposit_event(RawEvent::KittyCreated(user, new_kitty_id, kitty_created));
+
+            let now = <frame_system::Module<T>>::block_number();
+            ensure!(kitty_1.cooldown_end <= now, Error::<T>::ParentOnCooldown);
+            ensure!(kitty_2.cooldown_end <= now, Error::<T>::ParentOnCooldown);
+
+            let (kitty_created, new_kitty_id) = Self::breed_new(
+                user.clone(),
+                kitti_id_1 as KittyId,
+                kitty_id_2 as KittyId,
+                &kitty_1,
+                &kitty_2,
+            )?;
+            let gender = kitty_created.gender();
+            let attributes = kitty_created.attributes();
+            Self::deposit_event(RawEvent::KittyCreated(user, new_kitty_id, kitty_created, gender, attributes));
+            Ok(())
+        }
+
+        /// Transfer a kitty to another account
+        #[weight = 10_000]
+        pub fn transfer(origin, to: T::AccountId, kitty_id: KittyId) -> DispatchResult {
+            let sender = ensure_signed(origin)?;
+            ensure!(Kitties::<T>::contains_key(&sender, kitty_id), Error::<T>::KittyNotExixting);
+
+            Self::do_transfer(&sender, &to, kitty_id)?;
+
+            Self::deposit_event(RawEvent::KittyTransferred(sender, to, kitty_id));
+            Ok(())
+        }
+
+        /// List a kitty for sale at `new_price`, or de-list it by passing `None`.
+        #[weight = 10_000]
+        pub fn set_price(origin, kitty_id: KittyId, new_price: Option<BalanceOf<T>>) -> DispatchResult {
+            let sender = ensure_signed(origin)?;
+            ensure!(Kitties::<T>::contains_key(&sender, kitty_id), Error::<T>::NotOwner);
+
+            match new_price {
+                Some(price) => KittyPrices::<T>::insert(&sender, kitty_id, price),
+                None => KittyPrices::<T>::remove(&sender, kitty_id),
+            }
+
+            Self::deposit_event(RawEvent::PriceSet(sender, kitty_id, new_price));
+            Ok(())
+        }
+
+        /// Buy a kitty listed for sale, paying at most `max_price`.
+        #[weight = 10_000]
+        pub fn buy(origin, owner: T::AccountId, kitty_id: KittyId, max_price: BalanceOf<T>) -> DispatchResult {
+            let buyer = ensure_signed(origin)?;
+            let price = KittyPrices::<T>::get(&owner, kitty_id).ok_or(Error::<T>::NotForSale)?;
+            ensure!(price <= max_price, Error::<T>::BidTooLow);
+
+            // Money moves first; nothing below this line is allowed to fail.
+            T::Currency::transfer(&buyer, &owner, price, ExistenceRequirement::KeepAlive)?;
+
+            Self::do_transfer(&owner, &buyer, kitty_id)?;
+
+            Self::deposit_event(RawEvent::KittyBought(buyer, owner, kitty_id, price));
             Ok(())
         }
     }
@@ -107,11 +241,125 @@ decl_module! {
 impl<T: Config> Module<T> {
     // add code here
 
+    /// Append `kitty_id` to `owner`'s enumerable index and bump their count.
+    fn append_owned_kitty(owner: &T::AccountId, kitty_id: KittyId) -> DispatchResult {
+        let count = Self::owned_kitties_count(owner);
+        let new_count = count
+            .checked_add(1)
+            .ok_or(Error::<T>::OwnedKittiesCountOverflow)?;
+
+        OwnedKittiesIndex::<T>::insert(owner, count, kitty_id);
+        OwnedKittiesCount::<T>::insert(owner, new_count);
+        Ok(())
+    }
+
+    /// Remove `kitty_id` from `owner`'s enumerable index using swap-and-pop: the kitty at the
+    /// last index is moved into the removed kitty's slot so the index stays dense.
+    fn remove_owned_kitty(owner: &T::AccountId, kitty_id: KittyId) -> DispatchResult {
+        let count = Self::owned_kitties_count(owner);
+        let new_count = count
+            .checked_sub(1)
+            .ok_or(Error::<T>::OwnedKittiesCountUnderflow)?;
+
+        let removed_index = (0..count)
+            .find(|i| OwnedKittiesIndex::<T>::get(owner, i) == kitty_id)
+            .ok_or(Error::<T>::KittyNotExixting)?;
+
+        if removed_index != new_count {
+            let last_kitty_id = OwnedKittiesIndex::<T>::get(owner, new_count);
+            OwnedKittiesIndex::<T>::insert(owner, removed_index, last_kitty_id);
+        }
+        OwnedKittiesIndex::<T>::remove(owner, new_count);
+        OwnedKittiesCount::<T>::insert(owner, new_count);
+        Ok(())
+    }
+
+    /// Move a kitty from `from` to `to`, updating both accounts' enumerable indexes and the
+    /// global owner lookup.
+    /// Fails with `KittyNotExixting` if `from` does not hold the kitty; does not check the sale
+    /// price.
+    fn do_transfer(from: &T::AccountId, to: &T::AccountId, kitty_id: KittyId) -> DispatchResult {
+        let kitty = Kitties::<T>::get(from, kitty_id).ok_or(Error::<T>::KittyNotExixting)?;
+
+        Self::remove_owned_kitty(from, kitty_id)?;
+        Kitties::<T>::remove(from, kitty_id);
+
+        Kitties::<T>::insert(to, kitty_id, kitty);
+        Self::append_owned_kitty(to, kitty_id)?;
+        KittyOwner::<T>::insert(kitty_id, to);
+
+        // The new owner decides whether and at what price to re-list it.
+        KittyPrices::<T>::remove(from, kitty_id);
+        Ok(())
+    }
+
+    /// Record a freshly minted kitty in both the per-owner and the global storage items.
+    fn register_kitty(
+        owner: &T::AccountId,
+        kitty_id: KittyId,
+        kitty: &Kitty<T::BlockNumber>,
+    ) -> DispatchResult {
+        Kitties::<T>::insert(owner, kitty_id, kitty.clone());
+        Self::append_owned_kitty(owner, kitty_id)?;
+
+        AllKitties::<T>::insert(kitty_id, kitty.clone());
+        KittyOwner::<T>::insert(kitty_id, owner);
+
+        let all_count = Self::all_kitties_count();
+        let new_all_count = all_count
+            .checked_add(1)
+            .ok_or(Error::<T>::AllKittiesCountOverflow)?;
+        AllKittiesByIndex::insert(all_count, kitty_id);
+        AllKittiesCount::put(new_all_count);
+        Ok(())
+    }
+
+    /// Whether any stored kitty already carries this exact DNA.
+    fn dna_exists(dna: &[u8; 16]) -> bool {
+        Kitties::<T>::iter().any(|(_, _, kitty)| &kitty.dna == dna)
+    }
+
+    /// Cooldown length for a kitty of the given generation: `CooldownDuration` shifted left by
+    /// `min(gen, MAX_COOLDOWN_GENERATION)`, so higher-generation kitties breed more slowly.
+    fn cooldown_for(gen: u16) -> T::BlockNumber {
+        let shift = gen.min(MAX_COOLDOWN_GENERATION);
+        T::CooldownDuration::get().saturating_mul(T::BlockNumber::from(1u16 << shift))
+    }
+
+    /// Hash the block randomness, `sender`, the extrinsic index and a freshly bumped `Nonce`
+    /// into a 128bit value. `random_seed()` is constant for the whole block, so without the
+    /// nonce two calls in the same block could otherwise derive identical (or all-zero) output.
+    fn next_randomness(sender: &T::AccountId) -> [u8; 16] {
+        let nonce = Nonce::get();
+        Nonce::put(nonce.wrapping_add(1));
+
+        let payload = (
+            <pallet_randomness_collective_flip::Module<T> as Randomness<T::Hash>>::random_seed(),
+            sender,
+            <frame_system::Module<T>>::extrinsic_index(),
+            nonce,
+        );
+        payload.using_encoded(blake2_128)
+    }
+
+    /// Like `next_randomness`, but re-hashing with a freshly bumped nonce for as long as the
+    /// result collides with an existing kitty's DNA, so every minted kitty is guaranteed unique.
+    fn mint_unique_dna(sender: &T::AccountId) -> [u8; 16] {
+        loop {
+            let dna = Self::next_randomness(sender);
+            if !Self::dna_exists(&dna) {
+                return dna;
+            }
+        }
+    }
+
     fn breed_new(
         owner: T::AccountId,
-        kitty_1: &Kitty,
-        kitty_2: &Kitty,
-    ) -> Result<(Kitty, KittyId), &'static str> {
+        kitty_id_1: KittyId,
+        kitty_id_2: KittyId,
+        kitty_1: &Kitty<T::BlockNumber>,
+        kitty_2: &Kitty<T::BlockNumber>,
+    ) -> Result<(Kitty<T::BlockNumber>, KittyId), DispatchError> {
         // ensure first that both parents are not same sex
 
         ensure!(
@@ -119,25 +367,54 @@ impl<T: Config> Module<T> {
             Error::<T>::SameGenderParents
         );
 
-        let selector = 10u8;
-        //now we derive gender from dna
+        // Each byte gets its own selector mask, drawn from fresh randomness, so siblings from the
+        // same parents don't all inherit the same fixed gene mix. The outer loop re-rolls the
+        // selector on every DNA collision, which is what guarantees the final result is unique.
         let mut new_dna = [0u8; 16];
-        let new_dna = {
-            for i in 0..kitty_1.0.len() {
-                new_dna[i] = combine_dna(kitty_1.0[i], kitty_2.0[i], selector);
+        loop {
+            let selector = Self::next_randomness(&owner);
+            for i in 0..kitty_1.dna.len() {
+                new_dna[i] = combine_dna(kitty_1.dna[i], kitty_2.dna[i], selector[i]);
             }
-            new_dna
-        };
-        let new_kitty = Kitty(new_dna);
-        let mut next_kitty_id = Self::next_kitty_id();
+            if !Self::dna_exists(&new_dna) {
+                break;
+            }
+        }
 
-        Kitties::<T>::insert(owner, next_kitty_id, new_kitty.clone());
+        let gen = core::cmp::max(kitty_1.gen, kitty_2.gen)
+            .checked_add(1)
+            .ok_or(Error::<T>::GenerationOverflow)?;
+        let now = <frame_system::Module<T>>::block_number();
+        let new_kitty = Kitty {
+            dna: new_dna,
+            gen,
+            cooldown_end: now.saturating_add(Self::cooldown_for(gen)),
+        };
 
+        let next_kitty_id = Self::next_kitty_id();
         ensure!(
-            next_kitty_id.checked_add(1).ok_or("err").is_ok(),
+            next_kitty_id.checked_add(1).is_some(),
             Error::<T>::MaxKittiesReachedNow
         );
-        Ok((new_kitty, next_kitty_id - 1))
+
+        Self::register_kitty(&owner, next_kitty_id, &new_kitty)?;
+        NextKittyId::put(next_kitty_id + 1);
+
+        // Both parents go on cooldown too, scaled to their own generation.
+        for id in [kitty_id_1, kitty_id_2] {
+            Kitties::<T>::mutate(&owner, id, |maybe_kitty| {
+                if let Some(kitty) = maybe_kitty {
+                    kitty.cooldown_end = now.saturating_add(Self::cooldown_for(kitty.gen));
+                }
+            });
+            AllKitties::<T>::mutate(id, |maybe_kitty| {
+                if let Some(kitty) = maybe_kitty {
+                    kitty.cooldown_end = now.saturating_add(Self::cooldown_for(kitty.gen));
+                }
+            });
+        }
+
+        Ok((new_kitty, next_kitty_id))
     }
 }
 